@@ -0,0 +1,407 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A tmux control-mode (`tmux -CC`) transport that replaces the FIFO
+//! relay. Instead of three named pipes in a temporary directory, the
+//! finder runs in a control-mode pane whose output is reconstructed from
+//! the line-oriented `%`-prefixed protocol tmux speaks on its stdout.
+
+use std::collections::HashMap;
+use std::io::BufRead as _;
+use std::io::BufReader;
+use std::io::Write as _;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::Stdio;
+
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use crate::TmuxCommand;
+
+
+/// A parsed control-mode notification.
+///
+/// Unknown `%` notifications are represented as [`Event::Other`] so the
+/// parser stays forward-compatible with tmux versions that introduce new
+/// notification types.
+#[derive(Debug, PartialEq)]
+enum Event {
+  /// A `%begin` notification; the value is its leading timestamp field.
+  Begin(u64),
+  /// A `%end` notification; the value is its leading timestamp field.
+  End(u64),
+  /// An `%error` notification; the value is its leading timestamp field.
+  Error(u64),
+  /// A `%output` notification: the pane id and its decoded bytes.
+  Output(String, Vec<u8>),
+  /// A `%exit` notification.
+  Exit,
+  /// Any line that is not one of the recognized notifications above; in
+  /// particular, the verbatim result text delivered between a command's
+  /// `%begin`/`%end` (including pane ids like `%3`).
+  Other,
+}
+
+
+/// Decode the octal escapes tmux applies to `%output` payloads.
+///
+/// Non-printable bytes are emitted as `\ooo` (three octal digits) and a
+/// literal backslash as `\\`; everything else is passed through.
+fn decode_octal(bytes: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let byte = bytes[i];
+    if byte != b'\\' {
+      let () = out.push(byte);
+      i += 1;
+      continue
+    }
+
+    // Peek at the up-to-three bytes following the backslash without
+    // copying the remainder of the input.
+    match bytes.get(i + 1..i + 4) {
+      Some(&[a, b, c])
+        if (b'0'..=b'7').contains(&a) && (b'0'..=b'7').contains(&b) && (b'0'..=b'7').contains(&c) =>
+      {
+        let value = (a - b'0') as u32 * 64 + (b - b'0') as u32 * 8 + (c - b'0') as u32;
+        let () = out.push(value as u8);
+        i += 4;
+      },
+      _ => {
+        // A doubled backslash, or anything we do not recognize, decodes
+        // to a single backslash.
+        let () = out.push(b'\\');
+        i += if bytes.get(i + 1) == Some(&b'\\') { 2 } else { 1 };
+      },
+    }
+  }
+  out
+}
+
+
+/// Parse a single control-mode line into an [`Event`].
+fn parse_line(line: &str) -> Event {
+  let mut fields = line.splitn(2, ' ');
+  let tag = fields.next().unwrap_or_default();
+  let rest = fields.next().unwrap_or_default();
+
+  let number = || rest.split(' ').next().unwrap_or_default().parse::<u64>().ok();
+
+  match tag {
+    "%begin" => number().map_or(Event::Other, Event::Begin),
+    "%end" => number().map_or(Event::Other, Event::End),
+    "%error" => number().map_or(Event::Other, Event::Error),
+    "%exit" => Event::Exit,
+    "%output" => {
+      let mut parts = rest.splitn(2, ' ');
+      let pane = parts.next().unwrap_or_default().to_string();
+      let data = decode_octal(parts.next().unwrap_or_default().as_bytes());
+      Event::Output(pane, data)
+    },
+    _ => Event::Other,
+  }
+}
+
+
+/// A live `tmux -CC` session we drive and read notifications from.
+struct Session {
+  /// The control-mode child process.
+  child: Child,
+  /// The control-mode command stream.
+  stdin: ChildStdin,
+  /// The line-buffered notification stream.
+  stdout: BufReader<std::process::ChildStdout>,
+  /// Scratch buffer used to reassemble notification lines across reads.
+  line: String,
+}
+
+impl Session {
+  /// Spawn `tmux -CC` in a fresh session.
+  fn spawn(tmux: &TmuxCommand) -> Result<Self> {
+    let mut command = tmux.command();
+    let mut child = command
+      .arg("-CC")
+      .arg("new-session")
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::null())
+      .spawn()
+      .context("failed to spawn `tmux -CC`")?;
+
+    let stdin = child.stdin.take().context("control-mode stdin missing")?;
+    let stdout = child.stdout.take().context("control-mode stdout missing")?;
+    Ok(Self {
+      child,
+      stdin,
+      stdout: BufReader::new(stdout),
+      line: String::new(),
+    })
+  }
+
+  /// Write a command line to the control-mode stream.
+  fn send(&mut self, command: &str) -> Result<()> {
+    let () = self
+      .stdin
+      .write_all(command.as_bytes())
+      .context("failed to write control-mode command")?;
+    let () = self
+      .stdin
+      .write_all(b"\n")
+      .context("failed to write control-mode command")?;
+    self.stdin.flush().context("failed to flush control-mode command")
+  }
+
+  /// Read the next control-mode line, buffering partial lines across
+  /// reads and stripping the trailing newline.
+  ///
+  /// Returns `None` once tmux closes its stdout.
+  fn next_line(&mut self) -> Result<Option<&str>> {
+    self.line.clear();
+    let read = self
+      .stdout
+      .read_line(&mut self.line)
+      .context("failed to read control-mode notification")?;
+    if read == 0 {
+      return Ok(None)
+    }
+    Ok(Some(self.line.trim_end_matches(['\r', '\n'])))
+  }
+}
+
+
+/// Build the shell command run in the finder pane.
+///
+/// The candidate list is delivered to the finder's stdin over a pipe
+/// from a staged tmux buffer — fzy reads its choices from stdin until
+/// EOF and its UI from `/dev/tty`, so a pipe (not typed keystrokes) is
+/// the only way to drive it. On exit the finder's status is recorded in
+/// `option`, the buffer is discarded, and the completion lock `channel`
+/// is released.
+fn finder_pipeline(command: &str, buffer: &str, option: &str, channel: &str) -> String {
+  format!(
+    "tmux show-buffer -b {buffer} | {command}; \
+     rc=$?; tmux set-option -g {option} \"$rc\"; \
+     tmux delete-buffer -b {buffer}; tmux wait-for -U {channel}"
+  )
+}
+
+
+/// Run the finder via the control-mode backend.
+///
+/// The candidate list is staged in a tmux buffer and piped into the
+/// finder running in a freshly split pane; the finder's rendered output
+/// is reconstructed from the `%output` notifications carrying that pane's
+/// id. Completion is awaited through a `wait-for` lock that the pane
+/// releases on exit — the lock form cannot drop an early release — and
+/// the exit status is read back from the recorded option. A premature
+/// `%exit` is treated as a failure.
+pub(crate) fn run(tmux: &TmuxCommand, command: &str, input: &[u8]) -> Result<(Vec<u8>, i32)> {
+  let mut session = Session::spawn(tmux)?;
+
+  // Per-invocation names for the completion lock, the staging buffer, and
+  // the option that carries the finder's exit code.
+  let id = std::process::id();
+  let channel = format!("fzy-tmux-{id}");
+  let buffer = format!("fzy-tmux-{id}");
+  let option = "@fzy-tmux-rc";
+
+  let mut output = Vec::new();
+
+  // Stage the candidate list in a paste buffer for the pane to pipe in.
+  let list = String::from_utf8_lossy(input);
+  let () = session.send(&format!("set-buffer -b {buffer} {}", quote(&list)))?;
+  let _result = read_frame(&mut session, &mut output, None)?;
+
+  // Take the completion lock before launching so a fast finish cannot
+  // release it before we are waiting.
+  let () = session.send(&format!("wait-for -L {channel}"))?;
+  let _result = read_frame(&mut session, &mut output, None)?;
+
+  // Launch the finder; tmux prints the new pane id as the command result.
+  let wrapped = finder_pipeline(command, &buffer, option, &channel);
+  let launch = format!("split-window -P -F '#{{pane_id}}' {}", quote(&wrapped));
+  let () = session.send(&launch)?;
+
+  // Resolve the pane id, retaining any output tmux emitted for the pane
+  // before its id was known.
+  let (pane, pending) = read_launch_frame(&mut session)?;
+  let pane = pane.trim().to_string();
+  ensure_pane(&pane)?;
+  if let Some(early) = pending.get(&pane) {
+    let () = output.extend_from_slice(early);
+  }
+
+  // Block on the completion lock, collecting the finder's rendered output
+  // until the pane releases the lock on exit.
+  let () = session.send(&format!("wait-for -L {channel}"))?;
+  let _result = read_frame(&mut session, &mut output, Some(pane.as_str()))?;
+
+  // Read the recorded exit code back from the option.
+  let () = session.send(&format!("show-option -gqv {option}"))?;
+  let status = read_frame(&mut session, &mut output, Some(pane.as_str()))?;
+  let status = status.trim().parse::<i32>().unwrap_or(1);
+
+  let _ = session.child.kill();
+  let _ = session.child.wait();
+  Ok((output, status))
+}
+
+
+/// Read the launch command's frame, returning its textual result and any
+/// `%output` seen meanwhile keyed by pane id.
+///
+/// The pane id is not known until the frame yields it, so output for the
+/// new pane that races ahead of the result is buffered here rather than
+/// discarded, to be replayed by the caller once the id is resolved.
+fn read_launch_frame(session: &mut Session) -> Result<(String, HashMap<String, Vec<u8>>)> {
+  let mut text = String::new();
+  let mut pending = HashMap::new();
+  let mut in_frame = false;
+  loop {
+    let line = match session.next_line()? {
+      Some(line) => line,
+      None => break Err(anyhow::anyhow!("control-mode stream closed before the finder started")),
+    };
+
+    match parse_line(line) {
+      Event::Begin(_) => in_frame = true,
+      Event::End(_) => break Ok((text, pending)),
+      Event::Error(_) => break Err(anyhow::anyhow!("tmux reported an error launching the finder")),
+      Event::Exit => break Err(anyhow::anyhow!("tmux exited before the finder started")),
+      Event::Output(id, data) => {
+        let () = pending.entry(id).or_default().extend_from_slice(&data);
+      },
+      Event::Other => {
+        if in_frame {
+          let () = text.push_str(line);
+          let () = text.push('\n');
+        }
+      },
+    }
+  }
+}
+
+
+/// Read one command-result frame, returning the in-frame textual result.
+///
+/// Asynchronous `%output` for `pane` is appended to `output` regardless
+/// of frame boundaries. A premature `%exit` (or a closed stream) is an
+/// error, as is an `%error` frame.
+fn read_frame(session: &mut Session, output: &mut Vec<u8>, pane: Option<&str>) -> Result<String> {
+  let mut text = String::new();
+  let mut in_frame = false;
+  loop {
+    let line = match session.next_line()? {
+      Some(line) => line,
+      None => break Err(anyhow::anyhow!("control-mode stream closed before the finder completed")),
+    };
+
+    match parse_line(line) {
+      Event::Begin(_) => in_frame = true,
+      Event::End(_) => break Ok(text),
+      Event::Error(_) => break Err(anyhow::anyhow!("tmux reported a command error")),
+      Event::Exit => break Err(anyhow::anyhow!("tmux exited before the finder completed")),
+      Event::Output(id, data) => {
+        if Some(id.as_str()) == pane {
+          let () = output.extend_from_slice(&data);
+        }
+      },
+      // Any other line inside a frame is verbatim command-result text
+      // (including pane ids like `%3`); outside a frame it is an
+      // asynchronous notification we ignore.
+      Event::Other => {
+        if in_frame {
+          let () = text.push_str(line);
+          let () = text.push('\n');
+        }
+      },
+    }
+  }
+}
+
+
+/// Ensure the launch command produced a pane id.
+fn ensure_pane(pane: &str) -> Result<()> {
+  if pane.starts_with('%') {
+    Ok(())
+  } else {
+    bail!("tmux did not report a pane id for the finder")
+  }
+}
+
+
+/// Single-quote a string for inclusion in a tmux command line.
+fn quote(value: &str) -> String {
+  format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that octal-escaped payloads decode back to raw bytes.
+  #[test]
+  fn octal_decoding() {
+    assert_eq!(decode_octal(b"plain"), b"plain");
+    assert_eq!(decode_octal(b"a\\012b"), b"a\nb");
+    assert_eq!(decode_octal(b"\\\\"), b"\\");
+  }
+
+
+  /// Check that the notification lines we care about parse correctly.
+  #[test]
+  fn line_parsing() {
+    assert_eq!(parse_line("%begin 123 4 1"), Event::Begin(123));
+    assert_eq!(parse_line("%end 123 4 1"), Event::End(123));
+    assert_eq!(parse_line("%error 123 4 1"), Event::Error(123));
+    assert_eq!(parse_line("%exit"), Event::Exit);
+    assert_eq!(
+      parse_line("%output %5 hi\\012"),
+      Event::Output("%5".to_string(), b"hi\n".to_vec())
+    );
+    assert_eq!(parse_line("%window-add @1"), Event::Other);
+    // A pane id result line is not a recognized notification; the reader
+    // interprets it as in-frame result text.
+    assert_eq!(parse_line("%3"), Event::Other);
+  }
+
+
+  /// The finder pane pipes its candidates in from the staged buffer and
+  /// releases the completion lock on exit.
+  #[test]
+  fn finder_pipeline_feeds_stdin() {
+    let pipeline = finder_pipeline("fzy --lines 50", "buf", "@rc", "chan");
+    assert!(
+      pipeline.starts_with("tmux show-buffer -b buf | fzy --lines 50;"),
+      "candidates must be piped into the finder's stdin: {pipeline}"
+    );
+    assert!(pipeline.contains("tmux set-option -g @rc \"$rc\""));
+    assert!(pipeline.trim_end().ends_with("tmux wait-for -U chan"));
+  }
+
+
+  /// End-to-end smoke test of the feeding and output plumbing against a
+  /// real tmux server. Uses a non-interactive stand-in finder (`head`)
+  /// so the candidate stdin and exit-status paths are exercised without
+  /// keystrokes. Ignored by default as it needs a running tmux.
+  #[test]
+  #[ignore = "requires a running tmux server"]
+  fn smoke_pipe_through_finder() {
+    let tmux = TmuxCommand {
+      tmux: std::env::var_os("TMUX").map(|tmux| crate::filter_tmux(&tmux).to_os_string()),
+      socket_name: None,
+      socket_path: None,
+    };
+    let (output, status) = run(&tmux, "head -n1", b"first\nsecond\nthird\n").unwrap();
+    assert_eq!(status, 0);
+    assert!(
+      String::from_utf8_lossy(&output).contains("first"),
+      "expected the piped candidates to reach the finder"
+    );
+  }
+}
@@ -1,17 +1,27 @@
 // Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::fs;
 use std::io;
 use std::io::stdin;
 use std::io::stdout;
 use std::io::Read;
+use std::io::Write;
 use std::iter::once;
 use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
 use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 use std::process::exit;
 use std::process::Command;
 use std::process::Stdio;
@@ -28,6 +38,8 @@ use libc::mode_t;
 use libc::S_IRWXU;
 use tempfile::TempDir;
 
+mod control_mode;
+
 
 fn make_fifo(path: &Path, mode: mode_t) -> Result<()> {
   let cpath = path
@@ -44,6 +56,185 @@ fn make_fifo(path: &Path, mode: mode_t) -> Result<()> {
 }
 
 
+/// Check whether the file referenced by `fd` is a pipe/FIFO.
+#[cfg(target_os = "linux")]
+fn is_fifo(fd: RawFd) -> bool {
+  let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+  let rc = unsafe { libc::fstat(fd, stat.as_mut_ptr()) };
+  // SAFETY: `fstat` initialized the structure on success.
+  rc == 0 && (unsafe { stat.assume_init() }.st_mode & libc::S_IFMT) == libc::S_IFIFO
+}
+
+
+/// Move all data from `src` to `dst` in-kernel using `splice(2)`.
+///
+/// `splice` requires at least one of the descriptors to refer to a pipe;
+/// the kernel reports an unsupported pairing via `EINVAL`, in which case
+/// `Ok(false)` is returned so the caller can fall back to a userspace
+/// copy. `Ok(true)` signals that everything up to EOF was forwarded.
+#[cfg(target_os = "linux")]
+fn splice_all(src: RawFd, dst: RawFd) -> io::Result<bool> {
+  // Ask to move as much as the kernel is willing to in one go; splice
+  // internally caps this at the pipe capacity.
+  const CHUNK: usize = usize::MAX & !(4096 - 1);
+
+  loop {
+    let rc = unsafe {
+      libc::splice(
+        src,
+        std::ptr::null_mut(),
+        dst,
+        std::ptr::null_mut(),
+        CHUNK,
+        (libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE) as libc::c_uint,
+      )
+    };
+
+    if rc == 0 {
+      break Ok(true)
+    } else if rc < 0 {
+      let err = io::Error::last_os_error();
+      match err.raw_os_error() {
+        // Only a signal interruption warrants a retry. We operate on
+        // blocking descriptors, so `EAGAIN` is not expected; retrying it
+        // would busy-loop were a descriptor ever non-blocking.
+        Some(libc::EINTR) => continue,
+        Some(libc::EINVAL) => break Ok(false),
+        _ => break Err(err),
+      }
+    }
+  }
+}
+
+
+/// Forward all data from `src` to `dst`, preferring an in-kernel
+/// `splice(2)` path on Linux and falling back to a userspace copy
+/// elsewhere or when `splice` does not apply to the descriptor pair.
+fn forward<R, W>(src: &mut R, dst: &mut W) -> Result<()>
+where
+  R: Read + AsRawFd,
+  W: Write + AsRawFd,
+{
+  #[cfg(target_os = "linux")]
+  {
+    let src_fd = src.as_raw_fd();
+    let dst_fd = dst.as_raw_fd();
+    // We only take the `splice` path when the destination is a pipe. The
+    // stdin -> FIFO leg always satisfies this; the FIFO -> stdout leg only does when
+    // our own stdout is itself a pipe, so a tty or regular-file peer
+    // falls through to the userspace copy below. `EINVAL` from the
+    // kernel provides a further backstop.
+    if is_fifo(dst_fd)
+      && splice_all(src_fd, dst_fd).context("failed to splice data between pipes")?
+    {
+      return Ok(())
+    }
+  }
+
+  let _cnt = io::copy(src, dst).context("failed to copy data")?;
+  Ok(())
+}
+
+
+/// A single selection-history record for a candidate line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HistoryEntry {
+  /// How often the line has been picked.
+  hits: u64,
+  /// When the line was last picked, in seconds since the Unix epoch.
+  last_used: u64,
+}
+
+
+/// The current time in seconds since the Unix epoch.
+fn now_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs())
+}
+
+
+/// Determine the path of the selection-history file.
+fn history_path() -> Option<PathBuf> {
+  let base = env::var_os("XDG_DATA_HOME")
+    .map(PathBuf::from)
+    .filter(|path| path.is_absolute())
+    .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))?;
+  Some(base.join("fzy-tmux").join("history"))
+}
+
+
+/// Load the selection history, mapping each line to its record.
+///
+/// A missing or malformed file is treated as an empty history; lines we
+/// cannot parse are skipped so a partially corrupted file still works.
+fn load_history(path: &Path) -> HashMap<String, HistoryEntry> {
+  let mut history = HashMap::new();
+  let Ok(content) = fs::read_to_string(path) else {
+    return history
+  };
+
+  for line in content.lines() {
+    let mut fields = line.splitn(3, '\t');
+    if let (Some(hits), Some(last_used), Some(text)) =
+      (fields.next(), fields.next(), fields.next())
+    {
+      if let (Ok(hits), Ok(last_used)) = (hits.parse(), last_used.parse()) {
+        let _prev = history.insert(text.to_string(), HistoryEntry { hits, last_used });
+      }
+    }
+  }
+  history
+}
+
+
+/// Atomically persist the selection history to `path`.
+fn store_history(path: &Path, history: &HashMap<String, HistoryEntry>) -> Result<()> {
+  let dir = path
+    .parent()
+    .context("history path has no parent directory")?;
+  let () = fs::create_dir_all(dir)
+    .with_context(|| format!("failed to create directory `{}`", dir.display()))?;
+
+  let tmp = path.with_extension("tmp");
+  {
+    let mut file = File::create(&tmp)
+      .with_context(|| format!("failed to create `{}`", tmp.display()))?;
+    for (text, entry) in history {
+      let () = writeln!(file, "{}\t{}\t{text}", entry.hits, entry.last_used)
+        .context("failed to write history entry")?;
+    }
+  }
+
+  let () = fs::rename(&tmp, path)
+    .with_context(|| format!("failed to rename `{}` to `{}`", tmp.display(), path.display()))?;
+  Ok(())
+}
+
+
+/// Compute the frecency score of a history entry relative to `now`.
+fn frecency(entry: &HistoryEntry, now: u64) -> f64 {
+  let age_days = now.saturating_sub(entry.last_used) as f64 / 86_400.0;
+  let decay = 1.0 / (1.0 + age_days);
+  entry.hits as f64 * decay
+}
+
+
+/// Stable-sort `candidates` by descending frecency.
+///
+/// Previously picked lines float to the top ordered by score while
+/// never-seen candidates (score zero) retain their original relative
+/// order.
+fn reorder_by_frecency(candidates: &mut [&str], history: &HashMap<String, HistoryEntry>, now: u64) {
+  let () = candidates.sort_by(|a, b| {
+    let score = |line: &str| history.get(line).map_or(0.0, |entry| frecency(entry, now));
+    score(b)
+      .partial_cmp(&score(a))
+      .unwrap_or(std::cmp::Ordering::Equal)
+  });
+}
+
+
 /// Filter the contents of the `TMUX` environment variable.
 fn filter_tmux(tmux: &OsStr) -> &OsStr {
   let bytes = tmux.as_bytes();
@@ -62,9 +253,188 @@ fn filter_tmux(tmux: &OsStr) -> &OsStr {
 }
 
 
+/// The command line arguments understood by the program.
+struct Args {
+  /// Whether to enable selection-history reordering.
+  history: bool,
+  /// Whether to use the tmux control-mode backend instead of FIFOs.
+  control_mode: bool,
+  /// The finder executable to run (`--cmd`, `FZY_TMUX_CMD`).
+  cmd: Option<String>,
+  /// The value for the finder's `--lines` option (`--lines`,
+  /// `FZY_TMUX_LINES`).
+  lines: Option<String>,
+  /// Default finder options prepended before the passthrough arguments
+  /// (`--opt`, `FZY_TMUX_OPTS`).
+  opts: Vec<String>,
+  /// The tmux socket name to target (`-L`).
+  socket_name: Option<OsString>,
+  /// The tmux socket path to target (`-S`).
+  socket_path: Option<OsString>,
+  /// The remaining arguments to pass through to the finder.
+  passthrough: Vec<OsString>,
+}
+
+
+/// Interpret an expected flag value as UTF-8, erroring if it is missing
+/// or not valid Unicode.
+fn string_value(value: Option<OsString>, flag: &str) -> Result<String> {
+  value
+    .context(format!("`{flag}` requires a value"))?
+    .into_string()
+    .map_err(|_| anyhow::anyhow!("`{flag}` value is not valid UTF-8"))
+}
+
+
+/// Single-quote a word for safe inclusion in a `/bin/sh` command line.
+fn shell_quote(word: &str) -> String {
+  format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+
+/// Assemble the finder command line from its configured executable,
+/// `--lines` value, default options, and passthrough arguments.
+///
+/// Every component is quoted individually so arguments containing spaces
+/// or quotes survive intact into the shell `tmux` ultimately runs.
+fn finder_command(args: &Args) -> String {
+  let cmd = args.cmd.as_deref().unwrap_or("fzy");
+  let lines = args.lines.as_deref().unwrap_or("50");
+
+  let mut words = vec![shell_quote(cmd), "--lines".to_string(), shell_quote(lines)];
+  let () = words.extend(args.opts.iter().map(|opt| shell_quote(opt)));
+  let () = words.extend(
+    args
+      .passthrough
+      .iter()
+      .map(|arg| shell_quote(&arg.to_string_lossy())),
+  );
+  words.join(" ")
+}
+
+
+/// Parse our own arguments, leaving the first unrecognized token and
+/// everything after it for the finder.
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Args> {
+  let mut parsed = Args {
+    history: env::var_os("FZY_TMUX_HISTORY").is_some(),
+    control_mode: env::var_os("FZY_TMUX_CONTROL").is_some(),
+    cmd: env::var("FZY_TMUX_CMD").ok(),
+    lines: env::var("FZY_TMUX_LINES").ok(),
+    opts: env::var("FZY_TMUX_OPTS")
+      .ok()
+      .into_iter()
+      .flat_map(|opts| opts.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+      .collect(),
+    socket_name: None,
+    socket_path: None,
+    passthrough: Vec::new(),
+  };
+
+  let mut args = args.into_iter().peekable();
+  while let Some(arg) = args.peek() {
+    if arg == "--history" {
+      let _arg = args.next();
+      parsed.history = true;
+    } else if arg == "--control-mode" {
+      let _arg = args.next();
+      parsed.control_mode = true;
+    } else if arg == "--cmd" {
+      let _arg = args.next();
+      parsed.cmd = Some(string_value(args.next(), "--cmd")?);
+    } else if arg == "--lines" {
+      let _arg = args.next();
+      parsed.lines = Some(string_value(args.next(), "--lines")?);
+    } else if arg == "--opt" {
+      let _arg = args.next();
+      let () = parsed.opts.push(string_value(args.next(), "--opt")?);
+    } else if arg == "-L" {
+      let _arg = args.next();
+      parsed.socket_name = Some(args.next().context("`-L` requires a socket name")?);
+    } else if arg == "-S" {
+      let _arg = args.next();
+      parsed.socket_path = Some(args.next().context("`-S` requires a socket path")?);
+    } else {
+      break
+    }
+  }
+
+  parsed.passthrough = args.collect();
+  Ok(parsed)
+}
+
+
+/// A builder for the `tmux` commands we drive, centralizing the socket
+/// selection flags and the cleared environment.
+struct TmuxCommand {
+  /// The value to re-export as `TMUX`, if any.
+  tmux: Option<OsString>,
+  /// The socket name passed via `-L`, if any.
+  socket_name: Option<OsString>,
+  /// The socket path passed via `-S`, if any.
+  socket_path: Option<OsString>,
+}
+
+impl TmuxCommand {
+  /// Create a fresh `tmux` [`Command`] with the configured server
+  /// selection and environment applied.
+  fn command(&self) -> Command {
+    let mut command = Command::new("tmux");
+    let _ = command.env_clear();
+    if let Some(tmux) = &self.tmux {
+      let _ = command.env("TMUX", tmux);
+    }
+    if let Some(name) = &self.socket_name {
+      let _ = command.args([OsStr::new("-L"), name]);
+    }
+    if let Some(path) = &self.socket_path {
+      let _ = command.args([OsStr::new("-S"), path]);
+    }
+    command
+  }
+}
+
+
 fn main() -> Result<()> {
-  let tmux = env::var_os("TMUX").context("TMUX variable not found")?;
-  let tmux = filter_tmux(&tmux);
+  let args = parse_args(env::args_os().skip(1))?;
+  let finder = finder_command(&args);
+
+  // An explicitly selected server relaxes the requirement that we be
+  // running inside a tmux session ourselves.
+  let tmux = match env::var_os("TMUX") {
+    Some(tmux) => Some(filter_tmux(&tmux).to_os_string()),
+    None => {
+      ensure!(
+        args.socket_name.is_some() || args.socket_path.is_some(),
+        "TMUX variable not found"
+      );
+      None
+    },
+  };
+
+  let tmux = TmuxCommand {
+    tmux,
+    socket_name: args.socket_name,
+    socket_path: args.socket_path,
+  };
+  let history = args.history;
+
+  // The control-mode backend is self-contained: it speaks to tmux over a
+  // pipe rather than through named FIFOs.
+  if args.control_mode {
+    let mut input = Vec::new();
+    let _cnt = stdin()
+      .lock()
+      .read_to_end(&mut input)
+      .context("failed to read standard input")?;
+
+    let (output, rc) = control_mode::run(&tmux, &finder, &input)?;
+    let () = stdout()
+      .lock()
+      .write_all(&output)
+      .context("failed to copy standard output")?;
+    exit(rc);
+  }
 
   // Create a bunch of named FIFOs that we can use for communicating
   // with the fzy instance running inside tmux.
@@ -79,15 +449,14 @@ fn main() -> Result<()> {
   }
 
   let fzy = format!(
-    "fzy --lines 50 $* < '{}' > '{}' 2>&1 && echo 0 > '{ret}' || echo 1 > '{ret}'",
+    "{finder} < '{}' > '{}' 2>&1 && echo 0 > '{ret}' || echo 1 > '{ret}'",
     fifo_in.display(),
     fifo_out.display(),
     ret = fifo_ret.display(),
   );
 
-  let _child = Command::new("tmux")
-    .env_clear()
-    .env("TMUX", tmux)
+  let _child = tmux
+    .command()
     .stdin(Stdio::null())
     .stdout(Stdio::null())
     .stderr(Stdio::null())
@@ -102,17 +471,59 @@ fn main() -> Result<()> {
     .open(&fifo_in)
     .context("failed to open stdin FIFO")?;
 
-  // Transparently forward our program's input to the fzy instance we
-  // spawned.
-  let _thread = thread::spawn(move || {
-    if let Err(err) = io::copy(&mut stdin().lock(), &mut fifo_in) {
-      eprintln!("failed to pipe standard input: {err}")
-    }
-  });
+  // When history reordering is requested we have to buffer stdin so we
+  // can rank the candidates before handing them to fzy; the history file
+  // itself is loaded up front as well.
+  let hist = if history {
+    let path = history_path();
+
+    let mut input = String::new();
+    let _cnt = stdin()
+      .lock()
+      .read_to_string(&mut input)
+      .context("failed to read standard input")?;
+    let mut candidates = input.lines().collect::<Vec<_>>();
+
+    let history = path.as_deref().map(load_history).unwrap_or_default();
+    let now = now_secs();
+    let () = reorder_by_frecency(&mut candidates, &history, now);
+
+    let reordered = candidates.join("\n");
+    let _thread = thread::spawn(move || {
+      if let Err(err) = fifo_in.write_all(reordered.as_bytes()) {
+        eprintln!("failed to pipe standard input: {err}")
+      }
+    });
+    Some((path, history))
+  } else {
+    // Transparently forward our program's input to the fzy instance we
+    // spawned.
+    let _thread = thread::spawn(move || {
+      if let Err(err) = forward(&mut stdin().lock(), &mut fifo_in) {
+        eprintln!("failed to pipe standard input: {err}")
+      }
+    });
+    None
+  };
 
   let mut fifo_out = File::open(&fifo_out).context("failed to open stdout FIFO")?;
-  let _cnt =
-    io::copy(&mut fifo_out, &mut stdout().lock()).context("failed to copy standard output")?;
+  // In history mode we tee the chosen line so it can be recorded; the
+  // default path forwards it straight through.
+  let selected = if hist.is_some() {
+    let mut buf = Vec::new();
+    let _cnt = fifo_out
+      .read_to_end(&mut buf)
+      .context("failed to read standard output")?;
+    let () = stdout()
+      .lock()
+      .write_all(&buf)
+      .context("failed to copy standard output")?;
+    Some(buf)
+  } else {
+    let () =
+      forward(&mut fifo_out, &mut stdout().lock()).context("failed to copy standard output")?;
+    None
+  };
 
   // Read exit code from FIFO.
   let mut fifo_ret = File::open(&fifo_ret).context("failed to open exit code FIFO")?;
@@ -125,6 +536,25 @@ fn main() -> Result<()> {
     .trim_end()
     .parse::<i32>()
     .context("failed to parse reported exit code")?;
+
+  // On a successful selection, bump the chosen line's frecency record and
+  // persist the updated history.
+  if rc == 0 {
+    if let (Some((Some(path), mut history)), Some(selected)) = (hist, selected) {
+      let line = String::from_utf8_lossy(&selected);
+      let line = line.trim_end_matches(['\n', '\r']);
+      if !line.is_empty() {
+        let entry = history.entry(line.to_string()).or_insert(HistoryEntry {
+          hits: 0,
+          last_used: 0,
+        });
+        entry.hits += 1;
+        entry.last_used = now_secs();
+        let () = store_history(&path, &history)?;
+      }
+    }
+  }
+
   exit(rc);
 }
 
@@ -149,4 +579,109 @@ mod tests {
     let tmux = filter_tmux(tmux);
     assert_eq!(tmux, OsStr::new("/tmp/tmux-1000/default"));
   }
+
+
+  /// Check that a stored history loads back unchanged, including lines
+  /// that themselves contain spaces.
+  #[test]
+  fn history_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("history");
+
+    let mut history = HashMap::new();
+    let _ = history.insert("plain".to_string(), HistoryEntry {
+      hits: 3,
+      last_used: 100,
+    });
+    let _ = history.insert("with spaces".to_string(), HistoryEntry {
+      hits: 1,
+      last_used: 42,
+    });
+
+    let () = store_history(&path, &history).unwrap();
+    let loaded = load_history(&path);
+    assert_eq!(loaded, history);
+  }
+
+
+  /// A missing history file loads as an empty history.
+  #[test]
+  fn history_missing_file() {
+    let dir = TempDir::new().unwrap();
+    let loaded = load_history(&dir.path().join("does-not-exist"));
+    assert!(loaded.is_empty());
+  }
+
+
+  /// Check that frequent/recent entries float up, unseen candidates keep
+  /// their original order, and ties stay stable.
+  #[test]
+  fn frecency_reordering() {
+    let now = 10 * 86_400;
+    let mut history = HashMap::new();
+    // Frequently and recently picked.
+    let _ = history.insert("recent".to_string(), HistoryEntry {
+      hits: 5,
+      last_used: now,
+    });
+    // Picked once, long ago; a lower score than `recent`.
+    let _ = history.insert("old".to_string(), HistoryEntry {
+      hits: 1,
+      last_used: 0,
+    });
+
+    let mut candidates = vec!["unseen-a", "old", "unseen-b", "recent", "unseen-c"];
+    let () = reorder_by_frecency(&mut candidates, &history, now);
+
+    // Scored entries lead, highest first.
+    assert_eq!(&candidates[..2], &["recent", "old"]);
+    // Unseen entries trail in their original relative order.
+    assert_eq!(&candidates[2..], &["unseen-a", "unseen-b", "unseen-c"]);
+  }
+
+
+  /// Socket flags are captured, parsing stops at the first passthrough
+  /// token, and a missing flag value is an error.
+  #[test]
+  fn argument_parsing() {
+    let args = parse_args(["-L", "work", "query", "-S", "ignored"].map(OsString::from)).unwrap();
+    assert_eq!(args.socket_name.as_deref(), Some(OsStr::new("work")));
+    // `-S` appears after the first passthrough token, so it is not parsed
+    // as one of our flags.
+    assert!(args.socket_path.is_none());
+    assert_eq!(args.passthrough, ["query", "-S", "ignored"].map(OsString::from));
+
+    assert!(parse_args(["-L"].map(OsString::from)).is_err());
+  }
+
+
+  /// Lock down the `'\''` escaping the finder command relies on.
+  #[test]
+  fn shell_quoting() {
+    assert_eq!(shell_quote("plain"), "'plain'");
+    assert_eq!(shell_quote("a b"), "'a b'");
+    assert_eq!(shell_quote(""), "''");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+  }
+
+
+  /// The finder command is assembled from quoted words, honoring
+  /// overrides and passthrough arguments.
+  #[test]
+  fn finder_command_assembly() {
+    let args = Args {
+      history: false,
+      control_mode: false,
+      cmd: Some("my-fzy".to_string()),
+      lines: Some("20".to_string()),
+      opts: vec!["--prompt".to_string(), "> ".to_string()],
+      socket_name: None,
+      socket_path: None,
+      passthrough: vec![OsString::from("a b")],
+    };
+    assert_eq!(
+      finder_command(&args),
+      "'my-fzy' --lines '20' '--prompt' '> ' 'a b'"
+    );
+  }
 }